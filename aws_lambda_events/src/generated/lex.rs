@@ -86,6 +86,179 @@ pub struct Attachment {
     pub buttons: Option<Vec<HashMap<String, String>>>,
 }
 
+/// A fulfillment response returned from a Lex bot handler.
+///
+/// Unlike [`LexEvent`], which is deserialized from the shape Lex sends,
+/// `LexResponse` is built up with [`LexResponseBuilder`] and serialized back
+/// to the exact shape Lex expects for a reply.
+#[derive(Debug, Clone, Serialize)]
+pub struct LexResponse {
+    #[serde(rename = "sessionAttributes", skip_serializing_if = "Option::is_none")]
+    pub session_attributes: Option<HashMap<String, String>>,
+    #[serde(rename = "dialogAction")]
+    pub dialog_action: LexDialogResponse,
+}
+
+impl LexResponse {
+    pub fn builder(dialog_action: LexDialogResponse) -> LexResponseBuilder {
+        LexResponseBuilder::new(dialog_action)
+    }
+}
+
+/// Builds a [`LexResponse`] from a [`LexDialogResponse`], optionally
+/// attaching session attributes to round-trip back to Lex on the next turn.
+pub struct LexResponseBuilder {
+    session_attributes: Option<HashMap<String, String>>,
+    dialog_action: LexDialogResponse,
+}
+
+impl LexResponseBuilder {
+    pub fn new(dialog_action: LexDialogResponse) -> Self {
+        LexResponseBuilder {
+            session_attributes: None,
+            dialog_action,
+        }
+    }
+
+    pub fn session_attributes(mut self, session_attributes: HashMap<String, String>) -> Self {
+        self.session_attributes = Some(session_attributes);
+        self
+    }
+
+    pub fn build(self) -> LexResponse {
+        LexResponse {
+            session_attributes: self.session_attributes,
+            dialog_action: self.dialog_action,
+        }
+    }
+}
+
+/// The `fulfillmentState` Lex expects on a `Close` dialog action.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum LexFulfillmentState {
+    Fulfilled,
+    Failed,
+    ReadyForFulfillment,
+}
+
+/// The `dialogAction` of a Lex v1 fulfillment response, tagged by `type`.
+///
+/// Construct one with [`LexDialogResponse::close`], [`LexDialogResponse::elicit_slot`],
+/// [`LexDialogResponse::elicit_intent`], [`LexDialogResponse::confirm_intent`], or
+/// [`LexDialogResponse::delegate`], then attach an optional `message` and/or
+/// `responseCard` with [`LexDialogResponse::with_message`] /
+/// [`LexDialogResponse::with_response_card`]. A `Delegate` action carries
+/// neither, so both methods leave it unchanged.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum LexDialogResponse {
+    Close {
+        #[serde(rename = "fulfillmentState")]
+        fulfillment_state: LexFulfillmentState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<HashMap<String, String>>,
+        #[serde(rename = "responseCard", skip_serializing_if = "Option::is_none")]
+        response_card: Option<LexResponseCard>,
+    },
+    ElicitSlot {
+        #[serde(rename = "intentName")]
+        intent_name: String,
+        slots: Slots,
+        #[serde(rename = "slotToElicit")]
+        slot_to_elicit: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<HashMap<String, String>>,
+        #[serde(rename = "responseCard", skip_serializing_if = "Option::is_none")]
+        response_card: Option<LexResponseCard>,
+    },
+    ElicitIntent {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<HashMap<String, String>>,
+        #[serde(rename = "responseCard", skip_serializing_if = "Option::is_none")]
+        response_card: Option<LexResponseCard>,
+    },
+    ConfirmIntent {
+        #[serde(rename = "intentName")]
+        intent_name: String,
+        slots: Slots,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<HashMap<String, String>>,
+        #[serde(rename = "responseCard", skip_serializing_if = "Option::is_none")]
+        response_card: Option<LexResponseCard>,
+    },
+    Delegate {
+        slots: Slots,
+    },
+}
+
+impl LexDialogResponse {
+    pub fn close(fulfillment_state: LexFulfillmentState) -> Self {
+        LexDialogResponse::Close {
+            fulfillment_state,
+            message: None,
+            response_card: None,
+        }
+    }
+
+    pub fn elicit_slot(
+        intent_name: impl Into<String>,
+        slots: Slots,
+        slot_to_elicit: impl Into<String>,
+    ) -> Self {
+        LexDialogResponse::ElicitSlot {
+            intent_name: intent_name.into(),
+            slots,
+            slot_to_elicit: slot_to_elicit.into(),
+            message: None,
+            response_card: None,
+        }
+    }
+
+    pub fn elicit_intent() -> Self {
+        LexDialogResponse::ElicitIntent {
+            message: None,
+            response_card: None,
+        }
+    }
+
+    pub fn confirm_intent(intent_name: impl Into<String>, slots: Slots) -> Self {
+        LexDialogResponse::ConfirmIntent {
+            intent_name: intent_name.into(),
+            slots,
+            message: None,
+            response_card: None,
+        }
+    }
+
+    pub fn delegate(slots: Slots) -> Self {
+        LexDialogResponse::Delegate { slots }
+    }
+
+    pub fn with_message(mut self, message: HashMap<String, String>) -> Self {
+        match &mut self {
+            LexDialogResponse::Close { message: m, .. }
+            | LexDialogResponse::ElicitSlot { message: m, .. }
+            | LexDialogResponse::ElicitIntent { message: m, .. }
+            | LexDialogResponse::ConfirmIntent { message: m, .. } => *m = Some(message),
+            LexDialogResponse::Delegate { .. } => {}
+        }
+        self
+    }
+
+    pub fn with_response_card(mut self, response_card: LexResponseCard) -> Self {
+        match &mut self {
+            LexDialogResponse::Close { response_card: c, .. }
+            | LexDialogResponse::ElicitSlot { response_card: c, .. }
+            | LexDialogResponse::ElicitIntent { response_card: c, .. }
+            | LexDialogResponse::ConfirmIntent { response_card: c, .. } => {
+                *c = Some(response_card)
+            }
+            LexDialogResponse::Delegate { .. } => {}
+        }
+        self
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -97,4 +270,71 @@ mod test {
         let data = include_bytes!("fixtures/example-lex-event.json");
         let _: LexEvent = serde_json::from_slice(data).unwrap();
     }
+
+    #[test]
+    fn serializes_close_response() {
+        let mut message = HashMap::new();
+        message.insert("contentType".to_string(), "PlainText".to_string());
+        message.insert("content".to_string(), "All done!".to_string());
+
+        let response = LexResponse::builder(
+            LexDialogResponse::close(LexFulfillmentState::Fulfilled).with_message(message),
+        )
+        .build();
+
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["dialogAction"]["type"], "Close");
+        assert_eq!(value["dialogAction"]["fulfillmentState"], "Fulfilled");
+        assert_eq!(value["dialogAction"]["message"]["content"], "All done!");
+    }
+
+    #[test]
+    fn serializes_elicit_slot_response() {
+        let mut slots = Slots::new();
+        slots.insert("PizzaSize".to_string(), "Large".to_string());
+
+        let response =
+            LexResponse::builder(LexDialogResponse::elicit_slot("OrderPizza", slots, "PizzaSize"))
+                .build();
+
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["dialogAction"]["type"], "ElicitSlot");
+        assert_eq!(value["dialogAction"]["intentName"], "OrderPizza");
+        assert_eq!(value["dialogAction"]["slotToElicit"], "PizzaSize");
+        assert_eq!(value["dialogAction"]["slots"]["PizzaSize"], "Large");
+    }
+
+    #[test]
+    fn serializes_elicit_intent_response() {
+        let response = LexResponse::builder(LexDialogResponse::elicit_intent()).build();
+
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["dialogAction"]["type"], "ElicitIntent");
+    }
+
+    #[test]
+    fn serializes_confirm_intent_response() {
+        let mut slots = Slots::new();
+        slots.insert("PizzaSize".to_string(), "Large".to_string());
+
+        let response =
+            LexResponse::builder(LexDialogResponse::confirm_intent("OrderPizza", slots)).build();
+
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["dialogAction"]["type"], "ConfirmIntent");
+        assert_eq!(value["dialogAction"]["intentName"], "OrderPizza");
+        assert_eq!(value["dialogAction"]["slots"]["PizzaSize"], "Large");
+    }
+
+    #[test]
+    fn serializes_delegate_response() {
+        let mut slots = Slots::new();
+        slots.insert("PizzaSize".to_string(), "Large".to_string());
+
+        let response = LexResponse::builder(LexDialogResponse::delegate(slots)).build();
+
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["dialogAction"]["type"], "Delegate");
+        assert_eq!(value["dialogAction"]["slots"]["PizzaSize"], "Large");
+    }
 }
@@ -0,0 +1,126 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::time::{Duration, Instant};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Context>> = RefCell::new(None);
+}
+
+/// Per-invocation context threaded through a handler and the connection
+/// driving it.
+///
+/// `Context` is a cheap handle onto shared state: cloning it and reading it
+/// later (e.g. from within the handler's own future) observes updates the
+/// runtime makes, such as the deadline set once the `Invoke` frame for this
+/// invocation is decoded.
+#[derive(Debug, Clone)]
+pub struct Context {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    request_id: String,
+    deadline: Mutex<Option<Instant>>,
+    close_requested: AtomicBool,
+}
+
+impl Context {
+    pub fn new(request_id: impl Into<String>) -> Context {
+        Context {
+            inner: Arc::new(Inner {
+                request_id: request_id.into(),
+                deadline: Mutex::new(None),
+                close_requested: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// The AWS request id Lambda assigned to this invocation.
+    pub fn request_id(&self) -> &str {
+        &self.inner.request_id
+    }
+
+    /// Records the instant by which this invocation must complete. Called by
+    /// the connection once it decodes the `Invoke` frame's deadline.
+    pub(crate) fn set_deadline(&self, deadline: Instant) {
+        *self.inner.deadline.lock().unwrap() = Some(deadline);
+    }
+
+    /// Time left before Lambda gives up on this invocation, or `None` if no
+    /// deadline has been set yet. Handlers can poll this to shed load
+    /// proactively instead of doing work that will be discarded once the
+    /// runtime's own timeout fires.
+    pub fn remaining_time(&self) -> Option<Duration> {
+        let deadline = (*self.inner.deadline.lock().unwrap())?;
+        let now = Instant::now();
+        Some(if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        })
+    }
+
+    /// Asks the connection driving this invocation to close once the
+    /// response has been flushed. Useful when a handler detects a
+    /// fatal/rotated credential and wants to force a cold reconnect.
+    pub fn close_connection(&self) {
+        self.inner.close_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Reads and clears the close-connection flag. Called once by the
+    /// connection after an invocation resolves.
+    pub(crate) fn take_close_requested(&self) -> bool {
+        self.inner.close_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Runs `f` with `self` set as the current context, so code that doesn't
+    /// have a `Context` threaded to it directly (e.g. a panic hook) can still
+    /// reach it via [`Context::current`].
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let previous = CURRENT.with(|cell| cell.replace(Some(self.clone())));
+        let result = f();
+        CURRENT.with(|cell| *cell.borrow_mut() = previous);
+        result
+    }
+
+    /// The context of the invocation currently executing on this thread, if
+    /// any.
+    pub fn current() -> Option<Context> {
+        CURRENT.with(|cell| cell.borrow().clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn remaining_time_shrinks_towards_the_deadline() {
+        let ctx = Context::new("test-request-id");
+        ctx.set_deadline(Instant::now() + Duration::from_millis(50));
+
+        let first = ctx.remaining_time().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let second = ctx.remaining_time().unwrap();
+
+        assert!(second < first);
+    }
+
+    #[test]
+    fn close_connection_is_observed_once() {
+        let ctx = Context::new("test-request-id");
+        assert!(!ctx.take_close_requested());
+
+        ctx.close_connection();
+
+        assert!(ctx.take_close_requested());
+        assert!(!ctx.take_close_requested());
+    }
+}
@@ -1,220 +1,472 @@
-use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 
 use failure::Error;
-use futures::stream::FuturesUnordered;
-use futures::{Async, Future, Poll, Sink, Stream};
+use futures::sink::Sink;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use pin_project::pin_project;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
-use tower_service::{NewService, Service};
-use void::Void;
+use tokio::sync::Semaphore;
+use tokio::time::{delay_until, Delay, Instant};
+use tower::make::MakeService;
+use tower::Service;
 
 use super::context::Context;
 use super::error::{ConnectionError, RuntimeError};
 use super::proto;
 
-pub struct Server<S, I> {
-    new_service: S,
+/// Default cap on the number of `Invoke` requests a single `Connection` will
+/// dispatch to its service concurrently. See [`Server::max_in_flight`].
+const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+
+pub struct Server<S, I, Req> {
+    make_service: S,
     incoming: I,
+    max_connections: Option<Arc<Semaphore>>,
+    max_in_flight: usize,
+    _req: PhantomData<fn(Req)>,
 }
 
-impl<S, I> Server<S, I>
+impl<S, I, Req> Server<S, I, Req>
 where
-    S: NewService<Error = Error, InitError = Error> + 'static,
-    S::Future: Send + 'static,
+    S: MakeService<(), Req, MakeError = Error, Error = Error> + 'static,
     S::Service: Send + 'static,
-    <S::Service as Service>::Future: Send,
-    S::Request: DeserializeOwned + Send + 'static,
+    S::Future: Send + 'static,
+    <S::Service as Service<Req>>::Future: Send,
+    Req: DeserializeOwned + Send + 'static,
     S::Response: Serialize + Send + 'static,
-    I: Stream<Error = io::Error> + 'static,
-    I::Item: AsyncRead + AsyncWrite + Send + 'static,
+    I: Stream + 'static,
+    I::Item: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    pub fn new(new_service: S, incoming: I) -> Server<S, I> {
+    pub fn new(make_service: S, incoming: I) -> Server<S, I, Req> {
         Server {
-            new_service,
+            make_service,
             incoming,
+            max_connections: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            _req: PhantomData,
         }
     }
 
-    fn spawn_service(&mut self) -> impl Future<Item = S::Service, Error = ()> {
-        self.new_service
-            .new_service()
-            .then(|service_result| match service_result {
-                Ok(service) => Ok(service),
-                Err(err) => {
-                    error!("service error: {}", err);
-                    Err(())
-                }
-            })
+    /// Caps the number of connections this server will drive at once. Once
+    /// the limit is reached, accepting a new connection blocks until an
+    /// existing one closes.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(Arc::new(Semaphore::new(max_connections)));
+        self
     }
 
-    fn spawn(&mut self, stream: I::Item) -> Result<(), RuntimeError> {
-        let connection = self.spawn_service().and_then(|service| {
-            let connection = Connection::spawn(service, stream);
-            connection.then(|res| {
-                if let Err(err) = res {
-                    error!("connection error: {}", err);
-                }
-                Ok(())
-            })
-        });
-        ::tokio::spawn(connection);
+    /// Caps the number of `Invoke` requests each connection will dispatch to
+    /// its service concurrently. Defaults to [`DEFAULT_MAX_IN_FLIGHT`].
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
 
-        Ok(())
+    async fn spawn(&mut self, stream: I::Item) {
+        let permit = match &self.max_connections {
+            Some(semaphore) => Some(semaphore.clone().acquire_owned().await),
+            None => None,
+        };
+        let service = match self.make_service.make_service(()).await {
+            Ok(service) => service,
+            Err(err) => {
+                tracing::error!("service error: {}", err);
+                return;
+            }
+        };
+        let max_in_flight = self.max_in_flight;
+        tokio::spawn(async move {
+            let _permit = permit;
+            // `Connection::spawn` already logs the error before returning it.
+            let _ = Connection::spawn(service, stream, max_in_flight).await;
+        });
     }
 }
 
-impl<S, I> Future for Server<S, I>
+impl<S, I, Req> Server<S, I, Req>
 where
-    S: NewService<InitError = Error, Error = Error> + 'static,
+    S: MakeService<(), Req, MakeError = Error, Error = Error> + 'static,
     S::Service: Send + 'static,
-    <S::Service as Service>::Future: Send,
     S::Future: Send + 'static,
-    S::Request: DeserializeOwned + Send + 'static,
+    <S::Service as Service<Req>>::Future: Send,
+    Req: DeserializeOwned + Send + 'static,
     S::Response: Serialize + Send + 'static,
-    I: Stream<Error = io::Error> + 'static,
-    I::Item: AsyncRead + AsyncWrite + Send + 'static,
+    I: Stream + Unpin + 'static,
+    I::Item: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Item = ();
-    type Error = RuntimeError;
-
-    fn poll(&mut self) -> Poll<(), RuntimeError> {
-        loop {
-            if let Some(stream) = try_ready!(self.incoming.poll().map_err(RuntimeError::from_io)) {
-                self.spawn(stream)?;
-            } else {
-                return Ok(Async::Ready(()));
-            }
+    pub async fn run(mut self) -> Result<(), RuntimeError> {
+        while let Some(stream) = self.incoming.next().await {
+            self.spawn(stream).await;
         }
+        Ok(())
     }
 }
 
-struct Connection<S, Io>
+/// A decoded `Invoke` frame that couldn't be dispatched yet because the
+/// service wasn't ready. Held until `Service::poll_ready` clears, so the
+/// decoder can stop draining the connection instead of queuing unboundedly.
+struct PendingInvoke<Req> {
+    seq: u64,
+    received_at: Instant,
+    deadline: Instant,
+    ctx: Context,
+    payload: Req,
+}
+
+#[pin_project]
+struct Connection<S, Io, Req>
 where
-    S: Service,
+    S: Service<Req>,
     Io: AsyncRead + AsyncWrite + Send + 'static,
 {
     service: S,
-    decoder: proto::Decoder<ReadHalf<Io>, S::Request>,
+    decoder: proto::Decoder<ReadHalf<Io>, Req>,
     encoder: proto::Encoder<WriteHalf<Io>, S::Response>,
-    futures: FuturesUnordered<Invocation<S>>,
+    #[pin]
+    futures: FuturesUnordered<Invocation<S, Req>>,
+    pending: Option<PendingInvoke<Req>>,
+    max_in_flight: usize,
+    /// Set once an invocation has asked to close the connection (see
+    /// [`Context::close_connection`]). Once set, `poll_decoder` stops reading
+    /// new frames and the connection resolves once `futures` drains and the
+    /// encoder flushes.
+    closing: bool,
 }
 
-impl<S, Io> Connection<S, Io>
+impl<S, Io, Req> Connection<S, Io, Req>
 where
-    S: Service<Error = Error> + 'static,
-    S::Request: DeserializeOwned + Send + 'static,
+    S: Service<Req, Error = Error> + 'static,
+    Req: DeserializeOwned + Send + 'static,
     S::Response: Serialize + Send + 'static,
-    Io: AsyncRead + AsyncWrite + Send + 'static,
+    Io: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    fn spawn(service: S, io: Io) -> Self {
+    async fn spawn(service: S, io: Io, max_in_flight: usize) -> Result<(), ConnectionError> {
         let (r, w) = io.split();
         let decoder = proto::Decoder::new(r);
         let encoder = proto::Encoder::new(w);
 
-        Connection {
+        let result = Connection {
             service,
             decoder,
             encoder,
             futures: FuturesUnordered::new(),
+            pending: None,
+            max_in_flight,
+            closing: false,
+        }
+        .await;
+
+        if let Err(ref err) = result {
+            tracing::error!("connection torn down: {}", err);
         }
+        result
     }
 
-    fn poll_encoder(&mut self) -> Poll<(), ConnectionError> {
-        Ok(self.encoder.poll_complete()?)
+    fn poll_encoder(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), ConnectionError>> {
+        let this = self.project();
+        Pin::new(&mut *this.encoder).poll_flush(cx).map_err(Into::into)
     }
 
-    fn poll_futures(&mut self) -> Poll<(), ConnectionError> {
+    fn poll_futures(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), ConnectionError>> {
         loop {
-            if let Some((seq, result)) = try_ready!(self.futures.poll()) {
-                self.encoder
-                    .start_send(proto::Response::Invoke(seq, result))?;
-            } else {
-                return Ok(Async::Ready(()));
+            let this = self.as_mut().project();
+            match this.futures.poll_next(cx) {
+                Poll::Ready(Some((seq, result, close, span))) => {
+                    // Keep the invocation's span entered through encoding so
+                    // the response write is correlated with it too.
+                    let _enter = span.enter();
+                    let this = self.as_mut().project();
+                    if close {
+                        *this.closing = true;
+                    }
+                    Pin::new(&mut *this.encoder)
+                        .start_send(proto::Response::Invoke(seq, result))?;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
             }
         }
     }
 
-    fn poll_decoder(&mut self) -> Poll<(), ConnectionError> {
+    fn poll_decoder(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), ConnectionError>> {
         loop {
-            match self.decoder.poll() {
-                Ok(Async::Ready(Some(request))) => match request {
+            let this = self.as_mut().project();
+
+            // A frame is already decoded but the service wasn't ready for it;
+            // don't pull any more frames off the wire until it is. Drain it
+            // before honoring `closing` below, or it would be dropped with
+            // its `seq` never getting a response at all.
+            if let Some(pending) = this.pending.take() {
+                match this.service.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        this.futures
+                            .push(Self::dispatch(this.service, pending));
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
+                    Poll::Pending if *this.closing => {
+                        // The connection is shutting down; don't leave `seq`
+                        // waiting on backpressure that may never clear.
+                        Pin::new(&mut *this.encoder).start_send(proto::Response::Invoke(
+                            pending.seq,
+                            Err(failure::format_err!(
+                                "connection closing before invocation {} could be dispatched",
+                                pending.seq
+                            )),
+                        ))?;
+                    }
+                    Poll::Pending => {
+                        *this.pending = Some(pending);
+                        return Poll::Pending;
+                    }
+                }
+                continue;
+            }
+
+            if *this.closing {
+                // A handler asked us to close; stop reading new frames so
+                // the connection can drain and resolve once `futures` is
+                // empty and the encoder has flushed.
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.futures.len() >= *this.max_in_flight {
+                // Too many outstanding invocations for this connection;
+                // pause decoding rather than growing `futures` unboundedly.
+                return Poll::Pending;
+            }
+
+            match Pin::new(&mut *this.decoder).poll_next(cx) {
+                Poll::Ready(Some(Ok(request))) => match request {
                     proto::Request::Ping(seq) => {
-                        self.encoder.start_send(proto::Response::Ping(seq))?;
+                        Pin::new(&mut *this.encoder).start_send(proto::Response::Ping(seq))?;
                         continue;
                     }
-                    proto::Request::Invoke(seq, _deadline, ctx, payload) => {
-                        // TODO: enforce deadline
-                        let future = ctx.with(|| self.service.call(payload));
-                        self.futures.push(Invocation { seq, future, ctx });
+                    proto::Request::Invoke(seq, deadline, ctx, payload) => {
+                        let deadline = Instant::from_std(deadline);
+                        let pending = PendingInvoke {
+                            seq,
+                            received_at: Instant::now(),
+                            deadline,
+                            ctx,
+                            payload,
+                        };
+                        match this.service.poll_ready(cx) {
+                            Poll::Ready(Ok(())) => {
+                                this.futures.push(Self::dispatch(this.service, pending));
+                            }
+                            Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
+                            Poll::Pending => {
+                                *this.pending = Some(pending);
+                                return Poll::Pending;
+                            }
+                        }
                         continue;
                     }
                 },
-                Ok(Async::NotReady) => {
-                    return Ok(Async::NotReady);
-                }
-                Ok(Async::Ready(None)) => {
-                    return Ok(Async::Ready(()));
-                }
-                Err(proto::DecodeError::User(seq, err)) => {
-                    self.encoder
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(Err(proto::DecodeError::User(seq, err)))) => {
+                    tracing::warn!(seq, "user decode error: {}", err);
+                    Pin::new(&mut *this.encoder)
                         .start_send(proto::Response::Invoke(seq, Err(err)))?;
                     continue;
                 }
-                Err(proto::DecodeError::Frame(err)) => {
-                    return Err(err);
+                Poll::Ready(Some(Err(proto::DecodeError::Frame(err)))) => {
+                    tracing::error!("frame decode error, tearing down connection: {}", err);
+                    return Poll::Ready(Err(err.into()));
                 }
+                Poll::Pending => return Poll::Pending,
             }
         }
     }
+
+    fn dispatch(service: &mut S, pending: PendingInvoke<Req>) -> Invocation<S, Req> {
+        let PendingInvoke {
+            seq,
+            received_at,
+            deadline,
+            ctx,
+            payload,
+        } = pending;
+        ctx.set_deadline(deadline);
+
+        let span = tracing::info_span!(
+            "invocation",
+            seq,
+            request_id = %ctx.request_id(),
+            received_at = ?received_at,
+            deadline = ?deadline,
+            status = tracing::field::Empty,
+        );
+        let future = {
+            let _enter = span.enter();
+            ctx.with(|| service.call(payload))
+        };
+
+        Invocation {
+            seq,
+            future,
+            timer: delay_until(deadline),
+            ctx,
+            span,
+        }
+    }
 }
 
-impl<S, Io> Future for Connection<S, Io>
+impl<S, Io, Req> std::future::Future for Connection<S, Io, Req>
 where
-    S: Service<Error = Error> + 'static,
-    S::Request: DeserializeOwned + Send + 'static,
+    S: Service<Req, Error = Error> + 'static,
+    Req: DeserializeOwned + Send + 'static,
     S::Response: Serialize + Send + 'static,
-    Io: AsyncRead + AsyncWrite + Send + 'static,
+    Io: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Item = ();
-    type Error = ConnectionError;
+    type Output = Result<(), ConnectionError>;
 
-    fn poll(&mut self) -> Poll<(), ConnectionError> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
         // poll the decoder first, as it may create work for futures and encoder
-        let decoder_ready = self.poll_decoder()?.is_ready();
+        let decoder_ready = self.as_mut().poll_decoder(cx)?.is_ready();
         // poll the futures next, as they might create work for the encoder
-        let futures_ready = self.poll_futures()?.is_ready();
+        let futures_ready = self.as_mut().poll_futures(cx)?.is_ready();
         // poll the encoder last, as it will never create other work
-        let encoder_ready = self.poll_encoder()?.is_ready();
+        let encoder_ready = self.as_mut().poll_encoder(cx)?.is_ready();
 
         if encoder_ready && futures_ready && decoder_ready {
-            Ok(Async::Ready(()))
+            Poll::Ready(Ok(()))
         } else {
-            Ok(Async::NotReady)
+            Poll::Pending
         }
     }
 }
 
-struct Invocation<S: Service> {
+#[pin_project]
+struct Invocation<S: Service<Req>, Req> {
     seq: u64,
+    #[pin]
     future: S::Future,
+    #[pin]
+    timer: Delay,
     ctx: Context,
+    /// Correlates every poll of this invocation, and its eventual
+    /// completion, with `seq` and the AWS request id.
+    span: tracing::Span,
 }
 
-impl<S> Future for Invocation<S>
+impl<S, Req> std::future::Future for Invocation<S, Req>
 where
-    S: Service,
+    S: Service<Req, Error = Error>,
 {
-    type Item = (u64, Result<S::Response, S::Error>);
-    type Error = Void;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let seq = self.seq;
-        let future = &mut self.future;
-        self.ctx.with(|| match future.poll() {
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            Ok(Async::Ready(res)) => Ok(Async::Ready((seq, Ok(res)))),
-            Err(err) => Ok(Async::Ready((seq, Err(err)))),
-        })
+    /// `seq`, the handler's result, whether the handler asked (via
+    /// [`Context::close_connection`]) to close the connection once this
+    /// response has been flushed, and this invocation's span so the encoder
+    /// can stay correlated with it while writing the response.
+    type Output = (u64, Result<S::Response, Error>, bool, tracing::Span);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let seq = *this.seq;
+        let ctx = this.ctx;
+        let future = this.future;
+        let timer = this.timer;
+        let span = this.span;
+        let _enter = span.enter();
+
+        if let Poll::Ready(res) = ctx.with(|| future.poll(cx)) {
+            let close = ctx.take_close_requested();
+            span.record("status", &if res.is_ok() { "ok" } else { "err" });
+            return Poll::Ready((seq, res, close, span.clone()));
+        }
+
+        if timer.poll(cx).is_ready() {
+            // Drain the flag even on timeout: a handler that asked to close
+            // the connection right before its deadline fired shouldn't have
+            // that request silently lost.
+            let close = ctx.take_close_requested();
+            span.record("status", &"timeout");
+            tracing::warn!(seq, "invocation exceeded its deadline");
+            return Poll::Ready((
+                seq,
+                Err(failure::format_err!("invocation {} exceeded its deadline", seq)),
+                close,
+                span.clone(),
+            ));
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::time::Duration;
+    use tower::service_fn;
+
+    /// Exercises the regression fixed alongside the pending-invocation-drain
+    /// change: a handler that calls [`Context::close_connection`] and then
+    /// doesn't resolve before its deadline must still have the close request
+    /// observed, not silently lost on the timeout path.
+    #[tokio::test(start_paused = true)]
+    async fn invocation_drains_close_request_on_timeout() {
+        let ctx = Context::new("test-request-id");
+        let handler_ctx = ctx.clone();
+        let mut service = service_fn(move |_req: ()| {
+            let ctx = handler_ctx.clone();
+            async move {
+                ctx.close_connection();
+                futures::future::pending::<Result<(), Error>>().await
+            }
+        });
+
+        let pending = PendingInvoke {
+            seq: 1,
+            received_at: Instant::now(),
+            deadline: Instant::now() + Duration::from_millis(10),
+            ctx,
+            payload: (),
+        };
+        let invocation =
+            Connection::<_, tokio::io::DuplexStream, ()>::dispatch(&mut service, pending);
+
+        let (seq, result, close, _span) = invocation.await;
+
+        assert_eq!(seq, 1);
+        assert!(result.is_err());
+        assert!(close, "close request set before timeout must still be observed");
+    }
+
+    /// Same as above, but the handler resolves normally before its deadline;
+    /// the close request must still be drained.
+    #[tokio::test]
+    async fn invocation_drains_close_request_on_success() {
+        let ctx = Context::new("test-request-id");
+        let handler_ctx = ctx.clone();
+        let mut service = service_fn(move |_req: ()| {
+            let ctx = handler_ctx.clone();
+            async move {
+                ctx.close_connection();
+                Ok::<(), Error>(())
+            }
+        });
+
+        let pending = PendingInvoke {
+            seq: 2,
+            received_at: Instant::now(),
+            deadline: Instant::now() + Duration::from_secs(60),
+            ctx,
+            payload: (),
+        };
+        let invocation =
+            Connection::<_, tokio::io::DuplexStream, ()>::dispatch(&mut service, pending);
+
+        let (seq, result, close, _span) = invocation.await;
+
+        assert_eq!(seq, 2);
+        assert!(result.is_ok());
+        assert!(close);
     }
 }